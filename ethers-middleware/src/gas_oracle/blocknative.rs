@@ -1,4 +1,9 @@
-use std::{convert::TryInto, iter::FromIterator};
+use std::{
+    convert::TryInto,
+    iter::FromIterator,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use ethers_core::types::U256;
 
@@ -20,10 +25,14 @@ const BLOCKNATIVE_GAS_PRICE_ENDPOINT: &str = "https://api.blocknative.com/gaspri
 pub struct BlockNative {
     client: Client,
     url: Url,
-    gas_category: GasCategory,
+    confidence: u64,
+    chain_id: Option<u64>,
+    cache_ttl: Duration,
+    retries: u32,
+    cache: Arc<Mutex<Option<(Instant, BlockNativeGasResponse)>>>,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct BlockNativeGasResponse {
     system: Option<String>,
@@ -33,7 +42,7 @@ pub struct BlockNativeGasResponse {
     block_prices: Vec<BlockPrice>,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct BlockPrice {
     #[serde(rename = "blockNumber")]
     block_number: u64,
@@ -45,7 +54,7 @@ pub struct BlockPrice {
     estimated_prices: Vec<EstimatedPrice>,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct EstimatedPrice {
     confidence: u64,
     price: u64,
@@ -55,6 +64,21 @@ pub struct EstimatedPrice {
     max_fee_per_gas: f64,
 }
 
+/// Maps a chain id onto the `system`/`network` pair BlockNative is expected to report back in
+/// [`BlockNativeGasResponse`] for that chain, so a response can be checked against the chain it
+/// was requested for. This is best-effort and only used to log a warning on mismatch, never to
+/// reject the response (see [`BlockNative::query`](BlockNative::query)) — a chain id with no
+/// entry here (e.g. a chain added to `chainid` after this map was written) is simply not
+/// double-checked, not rejected.
+fn chain_id_to_network(chain_id: u64) -> Option<(&'static str, &'static str)> {
+    match chain_id {
+        1 => Some(("ethereum", "main")),
+        137 => Some(("matic", "main")),
+        56 => Some(("bsc", "main")),
+        _ => None,
+    }
+}
+
 fn gas_category_to_confidence(gas_category: GasCategory) -> u64 {
     match gas_category {
         GasCategory::SafeLow => 80,
@@ -64,40 +88,226 @@ fn gas_category_to_confidence(gas_category: GasCategory) -> u64 {
     }
 }
 
+/// Returns the `EstimatedPrice` at `confidence`, linearly interpolating `price`,
+/// `max_fee_per_gas` and `max_priority_fee_per_gas` between the two nearest buckets in
+/// `prices` when `confidence` isn't present exactly. Clamps to the lowest/highest bucket
+/// when `confidence` falls outside the range covered by `prices`.
+fn interpolate_confidence(prices: &[EstimatedPrice], confidence: u64) -> Option<EstimatedPrice> {
+    if let Some(exact) = prices.iter().find(|p| p.confidence == confidence) {
+        return Some(exact.clone());
+    }
+
+    let mut sorted = prices.to_vec();
+    sorted.sort_by_key(|p| p.confidence);
+    let lowest = sorted.first()?;
+    let highest = sorted.last()?;
+
+    if confidence <= lowest.confidence {
+        return Some(EstimatedPrice {
+            confidence,
+            ..lowest.clone()
+        });
+    }
+    if confidence >= highest.confidence {
+        return Some(EstimatedPrice {
+            confidence,
+            ..highest.clone()
+        });
+    }
+
+    sorted
+        .windows(2)
+        .find(|w| w[0].confidence <= confidence && confidence <= w[1].confidence)
+        .map(|w| {
+            let (lo, hi) = (&w[0], &w[1]);
+            let t = (confidence - lo.confidence) as f64 / (hi.confidence - lo.confidence) as f64;
+            EstimatedPrice {
+                confidence,
+                price: (lo.price as f64 + t * (hi.price as f64 - lo.price as f64)).round() as u64,
+                max_priority_fee_per_gas: lo.max_priority_fee_per_gas
+                    + t * (hi.max_priority_fee_per_gas - lo.max_priority_fee_per_gas),
+                max_fee_per_gas: lo.max_fee_per_gas + t * (hi.max_fee_per_gas - lo.max_fee_per_gas),
+            }
+        })
+}
+
 impl BlockNative {
     /// Creates a new [BlockNative](https://www.blocknative.com/gas-estimator) gas oracle
     pub fn new(api_key: &str) -> Self {
         let header_value = HeaderValue::from_str(api_key).unwrap();
         let headers = HeaderMap::from_iter([(AUTHORIZATION, header_value)]);
-        let client = ClientBuilder::new().default_headers(headers).build().unwrap();
+        let client = ClientBuilder::new()
+            .default_headers(headers)
+            .build()
+            .unwrap();
         Self {
             client,
             url: BLOCKNATIVE_GAS_PRICE_ENDPOINT.try_into().unwrap(),
-            gas_category: GasCategory::Standard,
+            confidence: gas_category_to_confidence(GasCategory::Standard),
+            chain_id: None,
+            cache_ttl: Duration::ZERO,
+            retries: 0,
+            cache: Arc::new(Mutex::new(None)),
         }
     }
 
     /// Sets the gas price category to be used when fetching the gas price.
     #[must_use]
     pub fn category(mut self, gas_category: GasCategory) -> Self {
-        self.gas_category = gas_category;
+        self.confidence = gas_category_to_confidence(gas_category);
+        self
+    }
+
+    /// Sets the confidence level, as a percentage, used when fetching the gas price.
+    ///
+    /// BlockNative only reports a handful of confidence buckets (e.g. 80/90/95/99); if `pct`
+    /// doesn't match one exactly, the price is linearly interpolated between the two nearest
+    /// buckets. Overrides any confidence set via [`category`](Self::category). Clamped to
+    /// `1..=99`.
+    #[must_use]
+    pub fn confidence(mut self, pct: u8) -> Self {
+        self.confidence = (pct as u64).clamp(1, 99);
+        self
+    }
+
+    /// Sets the chain id to fetch gas prices for, appended to the request as the `chainid`
+    /// query parameter. Required for any chain other than Ethereum mainnet (BlockNative's
+    /// default). [`query`](Self::query) never rejects a response based on `chain_id` — it only
+    /// logs a warning if the response's `system`/`network` fields don't match what's expected
+    /// for a recognized `chain_id`, or if `chain_id` isn't one we have expected values for.
+    #[must_use]
+    pub fn chain_id(mut self, chain_id: u64) -> Self {
+        self.url
+            .query_pairs_mut()
+            .append_pair("chainid", &chain_id.to_string());
+        self.chain_id = Some(chain_id);
+        self
+    }
+
+    /// Caches a successful response for `ttl`, so calls within that window reuse it instead of
+    /// issuing a fresh HTTP request. Disabled (the default) when `ttl` is `Duration::ZERO`.
+    #[must_use]
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Sets the number of retries, with exponential backoff capped at 30 seconds, for requests
+    /// that come back with a `429` or `5xx` status. Defaults to `0` (no retries). Only takes
+    /// effect on non-`wasm32` targets, since backing off needs an async sleep.
+    #[must_use]
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
         self
     }
 
     pub async fn query(&self) -> Result<BlockNativeGasResponse, GasOracleError> {
-        let resp = self
-            .client
-            .get(self.url.as_ref())
-            .send()
-            .await?;
-        let text = resp.text().await?;
-        match serde_json::from_str(&text) {
-            Ok(r) => Ok(r),
-            Err(e) => {
-                tracing::error!("error from blocknative: {e:?} (resp: {})", text);
-                Err(GasOracleError::SerdeJsonError(e.into()))
+        if self.cache_ttl > Duration::ZERO {
+            if let Some((fetched_at, res)) = &*self.cache.lock().unwrap() {
+                if fetched_at.elapsed() < self.cache_ttl {
+                    return Ok(res.clone());
+                }
             }
         }
+
+        let res = self.fetch_with_retries().await?;
+
+        if self.cache_ttl > Duration::ZERO {
+            *self.cache.lock().unwrap() = Some((Instant::now(), res.clone()));
+        }
+
+        Ok(res)
+    }
+
+    async fn fetch_with_retries(&self) -> Result<BlockNativeGasResponse, GasOracleError> {
+        // wasm32 has no sleep to back off with, so retrying there would just busy-spin.
+        #[cfg(target_arch = "wasm32")]
+        let retries = 0;
+        #[cfg(not(target_arch = "wasm32"))]
+        let retries = self.retries;
+
+        let mut attempt = 0;
+        loop {
+            let resp = self.client.get(self.url.as_ref()).send().await?;
+            let status = resp.status();
+            if (status.as_u16() == 429 || status.is_server_error()) && attempt < retries {
+                let backoff = Duration::from_millis(250u64.saturating_mul(1u64 << attempt.min(10)))
+                    .min(Duration::from_secs(30));
+                tracing::warn!("blocknative request failed with {status}, retrying in {backoff:?}");
+                #[cfg(not(target_arch = "wasm32"))]
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+                continue;
+            }
+
+            // Retries (if any) are exhausted at this point, so a 429/5xx here is terminal --
+            // surface it as the actual HTTP error instead of masking it as a JSON parse
+            // failure further down.
+            let resp = resp.error_for_status()?;
+            let text = resp.text().await?;
+            let res: BlockNativeGasResponse = match serde_json::from_str(&text) {
+                Ok(r) => r,
+                Err(e) => {
+                    tracing::error!("error from blocknative: {e:?} (resp: {})", text);
+                    return Err(GasOracleError::SerdeJsonError(e.into()));
+                }
+            };
+
+            if let Some(chain_id) = self.chain_id {
+                match chain_id_to_network(chain_id) {
+                    Some((system, network)) => {
+                        if res.system.as_deref() != Some(system)
+                            || res.network.as_deref() != Some(network)
+                        {
+                            tracing::warn!(
+                                "blocknative response system/network ({:?}/{:?}) doesn't match \
+                                 the expected {system}/{network} for chain id {chain_id}; the \
+                                 response may be for the wrong chain",
+                                res.system,
+                                res.network,
+                            );
+                        }
+                    }
+                    None => tracing::warn!(
+                        "can't verify blocknative response system/network ({:?}/{:?}) against \
+                         chain id {chain_id}; no expected values are known for this chain",
+                        res.system,
+                        res.network,
+                    ),
+                }
+            }
+
+            return Ok(res);
+        }
+    }
+
+    /// Returns, for each of the next `blocks_ahead` blocks in the order returned by the API,
+    /// the block's `block_number`, `base_fee_per_gas` (converted to wei) and the
+    /// `(max_fee, max_priority_fee)` pair at the configured confidence, so callers can target a
+    /// specific future block instead of only the head block.
+    pub async fn predict_fees(
+        &self,
+        blocks_ahead: usize,
+    ) -> Result<Vec<(u64, U256, U256, U256)>, GasOracleError> {
+        let res = self.query().await?;
+        res.block_prices
+            .iter()
+            .take(blocks_ahead)
+            .map(|block| {
+                let price = interpolate_confidence(&block.estimated_prices, self.confidence)
+                    .ok_or(GasOracleError::InvalidResponse)?;
+                let base_fee = U256::from((block.base_fee_per_gas * 100.0) as u64)
+                    * U256::from(GWEI_TO_WEI)
+                    / U256::from(100);
+                let max_fee = U256::from((price.max_fee_per_gas * 100.0) as u64)
+                    * U256::from(GWEI_TO_WEI)
+                    / U256::from(100);
+                let prio_fee = U256::from((price.max_priority_fee_per_gas * 100.0) as u64)
+                    * U256::from(GWEI_TO_WEI)
+                    / U256::from(100);
+                Ok((block.block_number, base_fee, max_fee, prio_fee))
+            })
+            .collect()
     }
 }
 
@@ -105,37 +315,88 @@ impl BlockNative {
 #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
 impl GasOracle for BlockNative {
     async fn fetch(&self) -> Result<U256, GasOracleError> {
-        todo!()
-        // let mut res = self.query().await?;
-        // let confidence = gas_category_to_confidence(self.gas_category);
-        // let price = res
-        //     .block_prices
-        //     .pop()
-        //     .unwrap()
-        //     .estimated_prices
-        //     .into_iter()
-        //     .find(|p| p.confidence == confidence)
-        //     .unwrap();
-        // Ok(U256::from((price.price * GWEI_TO_WEI) / 10))
+        let res = self.query().await?;
+        let price = interpolate_confidence(
+            &res.block_prices
+                .first()
+                .ok_or(GasOracleError::InvalidResponse)?
+                .estimated_prices,
+            self.confidence,
+        )
+        .ok_or(GasOracleError::InvalidResponse)?;
+        Ok(U256::from(price.price) * U256::from(GWEI_TO_WEI))
     }
 
     async fn estimate_eip1559_fees(&self) -> Result<(U256, U256), GasOracleError> {
-        let mut res = self.query().await?;
-        let confidence = gas_category_to_confidence(self.gas_category);
-        let block_prices = res
-            .block_prices
-            .pop()
-            .unwrap()
-            .estimated_prices
-            .into_iter()
-            .find(|p| p.confidence == confidence)
-            .unwrap();
-        let base_fee = U256::from((block_prices.max_fee_per_gas * 100.0) as u64) *
-            U256::from(GWEI_TO_WEI) /
-            U256::from(100);
-        let prio_fee = U256::from((block_prices.max_priority_fee_per_gas * 100.0) as u64 as u64) *
-            U256::from(GWEI_TO_WEI) /
-            U256::from(100);
+        let res = self.query().await?;
+        let block_prices = interpolate_confidence(
+            &res.block_prices
+                .first()
+                .ok_or(GasOracleError::InvalidResponse)?
+                .estimated_prices,
+            self.confidence,
+        )
+        .ok_or(GasOracleError::InvalidResponse)?;
+        let base_fee = U256::from((block_prices.max_fee_per_gas * 100.0) as u64)
+            * U256::from(GWEI_TO_WEI)
+            / U256::from(100);
+        let prio_fee = U256::from((block_prices.max_priority_fee_per_gas * 100.0) as u64)
+            * U256::from(GWEI_TO_WEI)
+            / U256::from(100);
         Ok((base_fee, prio_fee))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn price(confidence: u64, price: u64, max_fee: f64, max_priority_fee: f64) -> EstimatedPrice {
+        EstimatedPrice {
+            confidence,
+            price,
+            max_fee_per_gas: max_fee,
+            max_priority_fee_per_gas: max_priority_fee,
+        }
+    }
+
+    #[test]
+    fn interpolate_confidence_exact_hit() {
+        let prices = vec![
+            price(80, 10, 20.0, 1.0),
+            price(90, 20, 30.0, 2.0),
+            price(99, 30, 40.0, 3.0),
+        ];
+        let got = interpolate_confidence(&prices, 90).unwrap();
+        assert_eq!(got, price(90, 20, 30.0, 2.0));
+    }
+
+    #[test]
+    fn interpolate_confidence_clamps_below_lowest() {
+        let prices = vec![price(80, 10, 20.0, 1.0), price(99, 30, 40.0, 3.0)];
+        let got = interpolate_confidence(&prices, 10).unwrap();
+        assert_eq!(got, price(10, 10, 20.0, 1.0));
+    }
+
+    #[test]
+    fn interpolate_confidence_clamps_above_highest() {
+        let prices = vec![price(80, 10, 20.0, 1.0), price(99, 30, 40.0, 3.0)];
+        let got = interpolate_confidence(&prices, 100).unwrap();
+        assert_eq!(got, price(100, 30, 40.0, 3.0));
+    }
+
+    #[test]
+    fn interpolate_confidence_mid_bucket() {
+        let prices = vec![price(80, 10, 20.0, 1.0), price(90, 20, 30.0, 2.0)];
+        let got = interpolate_confidence(&prices, 85).unwrap();
+        assert_eq!(got.confidence, 85);
+        assert_eq!(got.price, 15);
+        assert!((got.max_fee_per_gas - 25.0).abs() < f64::EPSILON);
+        assert!((got.max_priority_fee_per_gas - 1.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn interpolate_confidence_empty_prices() {
+        assert_eq!(interpolate_confidence(&[], 90), None);
+    }
+}